@@ -21,11 +21,25 @@ impl Default for Box<ProfileValidator> {
 
 
 
-pub struct MultiHashProfileValidator {}
+/// Which `multihash::Hash` algorithms a node accepts when validating a peer's `ProfileId`. The
+/// algorithm a node mints its own ids with lives in `ProfileIdFactory`, which builds this
+/// validator from its own `trusted_algorithms`: a deployment migrates from one hash function to
+/// another by adding the new algorithm to `trusted_algorithms` everywhere first, only then
+/// making it the `preferred_algorithm`.
+pub struct MultiHashProfileValidator
+{
+    trusted_algorithms: Vec<multihash::Hash>,
+}
 
 impl Default for MultiHashProfileValidator
 {
-    fn default() -> Self { Self{} }
+    fn default() -> Self { Self::new( vec![multihash::Hash::Keccak256] ) }
+}
+
+impl MultiHashProfileValidator
+{
+    pub fn new(trusted_algorithms: Vec<multihash::Hash>) -> Self
+        { Self{ trusted_algorithms } }
 }
 
 impl ProfileValidator for MultiHashProfileValidator
@@ -36,6 +50,7 @@ impl ProfileValidator for MultiHashProfileValidator
         let id_hashalgo = multihash::decode(profile_id.0.as_slice())
             .map_err(|e| ErrorToBeSpecified::TODO(e.description().to_owned()))
             ?.alg;
+        if !self.trusted_algorithms.contains(&id_hashalgo) { return Ok(false); }
         let key_hash = multihash::encode(id_hashalgo, public_key.0.as_slice())
             .map_err(|e| ErrorToBeSpecified::TODO(e.description().to_owned()))?;
         Ok(key_hash == profile_id.0)
@@ -44,6 +59,42 @@ impl ProfileValidator for MultiHashProfileValidator
 
 
 
+/// Config for deriving and validating `ProfileId`s, so the Keccak256 coupling that used to be
+/// hardcoded into `Ed25519Signer::new` and `From<&PublicKey> for ProfileId` becomes a deployment
+/// choice instead. `preferred_algorithm` is what this node mints new `ProfileId`s with;
+/// `trusted_algorithms` is what it accepts from peers, which only needs to include
+/// `preferred_algorithm` too if this node wants to trust its own ids (the normal case).
+#[derive(Clone, Debug)]
+pub struct ProfileIdFactory
+{
+    preferred_algorithm: multihash::Hash,
+    trusted_algorithms:  Vec<multihash::Hash>,
+}
+
+impl ProfileIdFactory
+{
+    pub fn new(preferred_algorithm: multihash::Hash, trusted_algorithms: Vec<multihash::Hash>) -> Self
+        { Self{ preferred_algorithm, trusted_algorithms } }
+
+    pub fn profile_id(&self, public_key: &PublicKey) -> Result<ProfileId, ErrorToBeSpecified>
+    {
+        multihash::encode( self.preferred_algorithm, public_key.0.as_slice() )
+            .map(ProfileId)
+            .map_err( |e| ErrorToBeSpecified::TODO( e.description().to_owned() ) )
+    }
+
+    pub fn validator(&self) -> MultiHashProfileValidator
+        { MultiHashProfileValidator::new( self.trusted_algorithms.clone() ) }
+}
+
+impl Default for ProfileIdFactory
+{
+    fn default() -> Self
+        { Self::new( multihash::Hash::Keccak256, vec![multihash::Hash::Keccak256] ) }
+}
+
+
+
 pub trait SignatureValidator
 {
     fn validate_signature(&self, public_key: &PublicKey, data: &[u8], signature: &Signature)
@@ -67,14 +118,13 @@ pub struct Ed25519Signer
 
 impl Ed25519Signer
 {
-    pub fn new(private_key: &PrivateKey, public_key: &PublicKey) -> Result<Self, ErrorToBeSpecified>
+    pub fn new(private_key: &PrivateKey, public_key: &PublicKey, id_factory: &ProfileIdFactory)
+        -> Result<Self, ErrorToBeSpecified>
     {
-        let profile_hash = multihash::encode( multihash::Hash::Keccak256, public_key.0.as_slice() )
-            .map_err( |e| ErrorToBeSpecified::TODO( e.description().to_owned() ) )?;
+        let profile_id = id_factory.profile_id(public_key)?;
         let signer = dalek::Ed25519Signer::from_seed( private_key.0.as_slice() )
             .map_err( |e| ErrorToBeSpecified::TODO( e.description().to_owned() ) )?;
-        Ok( Self{ public_key: public_key.to_owned(), profile_id: ProfileId(profile_hash),
-                  signer: signer } )
+        Ok( Self{ public_key: public_key.to_owned(), profile_id, signer: signer } )
     }
 }
 
@@ -133,12 +183,11 @@ impl<'a> From<ed25519_dalek::PublicKey> for PublicKey {
 }
 
 impl<'a> From<&'a PublicKey> for ProfileId {
+    // Uses ProfileIdFactory::default(), i.e. Keccak256 - callers that need a different
+    // algorithm should go through ProfileIdFactory::profile_id() directly instead.
     fn from(public_key: &'a PublicKey) -> Self {
-        let hash = multihash::encode( multihash::Hash::Keccak256, public_key.0.as_slice() );
-        match hash {
-            Ok(hash) => ProfileId(hash),
-            Err(e) => panic!("TODO: This should never happen. Error: {}", e),
-        }
+        ProfileIdFactory::default().profile_id(public_key)
+            .unwrap_or_else( |e| panic!("TODO: This should never happen. Error: {:?}", e) )
     }
 }
 
@@ -155,6 +204,9 @@ impl CompositeValidator
 {
     pub fn compose(profile_validator: Box<ProfileValidator>, signature_validator: Box<SignatureValidator>) -> Self
         { Self{ profile_validator, signature_validator } }
+
+    pub fn with_id_factory(id_factory: &ProfileIdFactory, signature_validator: Box<SignatureValidator>) -> Self
+        { Self::compose( Box::new( id_factory.validator() ), signature_validator ) }
 }
 
 impl ProfileValidator for CompositeValidator
@@ -187,7 +239,7 @@ mod tests
         let public_key = PublicKey( b"\xEC\x17\x2B\x93\xAD\x5E\x56\x3B\xF4\x93\x2C\x70\xE1\x24\x50\x34\xC3\x54\x67\xEF\x2E\xFD\x4D\x64\xEB\xF8\x19\x68\x34\x67\xE2\xBF".to_vec() );
         let message = b"\xDD\xAF\x35\xA1\x93\x61\x7A\xBA\xCC\x41\x73\x49\xAE\x20\x41\x31\x12\xE6\xFA\x4E\x89\xA9\x7E\xA2\x0A\x9E\xEE\xE6\x4B\x55\xD3\x9A\x21\x92\x99\x2A\x27\x4F\xC1\xA8\x36\xBA\x3C\x23\xA3\xFE\xEB\xBD\x45\x4D\x44\x23\x64\x3C\xE8\x0E\x2A\x9A\xC9\x4F\xA5\x4C\xA4\x9F";
 
-        let signer = Ed25519Signer::new(&secret_key, &public_key).unwrap();
+        let signer = Ed25519Signer::new(&secret_key, &public_key, &ProfileIdFactory::default()).unwrap();
         let signature = signer.sign(message);
         let expected_signature = b"\xDC\x2A\x44\x59\xE7\x36\x96\x33\xA5\x2B\x1B\xF2\x77\x83\x9A\x00\x20\x10\x09\xA3\xEF\xBF\x3E\xCB\x69\xBE\xA2\x18\x6C\x26\xB5\x89\x09\x35\x1F\xC9\xAC\x90\xB3\xEC\xFD\xFB\xC7\xC6\x64\x31\xE0\x30\x3D\xCA\x17\x9C\x13\x8A\xC1\x7A\xD9\xBE\xF1\x17\x73\x31\xA7\x04";
         assert_eq!( signature.0.as_slice(), expected_signature as &[u8] );
@@ -0,0 +1,467 @@
+use std::rc::Rc;
+
+use curve25519_dalek::constants::ED25519_BASEPOINT_TABLE;
+use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
+use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::Identity;
+use futures::{future, Future, Sink, Stream};
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha512};
+
+use ::*;
+use crypto::ProfileIdFactory;
+
+
+
+/// 1-indexed position of a device among the `n` devices that jointly hold a `ProfileId`.
+pub type ParticipantIndex = u32;
+
+fn lagrange_coefficient(index: ParticipantIndex, others: &[ParticipantIndex]) -> Scalar
+{
+    let x_i = Scalar::from(index as u64);
+    others.iter().filter( |&&j| j != index )
+        .fold( Scalar::one(), |acc, &j|
+        {
+            let x_j = Scalar::from(j as u64);
+            acc * x_j * (x_j - x_i).invert()
+        } )
+}
+
+fn hash_to_scalar(parts: &[&[u8]]) -> Scalar
+{
+    let mut hasher = Sha512::new();
+    for part in parts { hasher.input(part); }
+    Scalar::from_hash(hasher)
+}
+
+
+
+// --- Pedersen/Feldman DKG -------------------------------------------------------------------
+
+/// What participant `i` broadcasts in round 1: commitments `g^{a_0}, g^{a_1}, ..., g^{a_{t-1}}`
+/// to the coefficients of its secret degree-`(t-1)` polynomial. `a_0` is `i`'s contribution to
+/// the eventual group secret, so `commitments[0]` is also `i`'s contribution to the group
+/// public key.
+#[derive(Clone, Debug)]
+pub struct DkgCommitment
+{
+    pub participant: ParticipantIndex,
+    pub commitments: Vec<EdwardsPoint>,
+}
+
+/// The private share `f_i(j)` participant `i` sends to participant `j` in round 1, alongside
+/// `i`'s `DkgCommitment` (broadcast to everyone, not just `j`).
+#[derive(Clone, Debug)]
+pub struct DkgShare
+{
+    pub from: ParticipantIndex,
+    pub to: ParticipantIndex,
+    pub value: Scalar,
+}
+
+struct DkgPolynomial
+{
+    coefficients: Vec<Scalar>,
+}
+
+impl DkgPolynomial
+{
+    fn sample(threshold: u32) -> Self
+    {
+        let mut rng = OsRng{};
+        let coefficients = (0..threshold).map( |_|
+        {
+            let mut bytes = [0u8; 64];
+            rng.fill_bytes(&mut bytes);
+            Scalar::from_bytes_mod_order_wide(&bytes)
+        } ).collect();
+        Self{ coefficients }
+    }
+
+    fn commitments(&self) -> Vec<EdwardsPoint>
+        { self.coefficients.iter().map( |a| &ED25519_BASEPOINT_TABLE * a ).collect() }
+
+    fn evaluate(&self, at: ParticipantIndex) -> Scalar
+    {
+        let x = Scalar::from(at as u64);
+        self.coefficients.iter().rev()
+            .fold( Scalar::zero(), |acc, coeff| acc * x + coeff )
+    }
+}
+
+/// One device's view of a DKG run: samples its own polynomial and produces what it must
+/// broadcast/send to the other `n-1` participants. Call `dkg_round1` once per device, exchange
+/// the results out of band (e.g. over `HomeSession`), then call `dkg_finalize` with everything
+/// received to arrive at the final `FrostKeyShare`.
+pub fn dkg_round1(own_index: ParticipantIndex, participants: &[ParticipantIndex], threshold: u32)
+    -> (DkgCommitment, Vec<DkgShare>)
+{
+    let polynomial = DkgPolynomial::sample(threshold);
+    let commitment = DkgCommitment{ participant: own_index, commitments: polynomial.commitments() };
+    let shares = participants.iter()
+        .map( |&to| DkgShare{ from: own_index, to, value: polynomial.evaluate(to) } )
+        .collect();
+    (commitment, shares)
+}
+
+fn verify_share(commitment: &DkgCommitment, share: &DkgShare) -> bool
+{
+    let x = Scalar::from(share.to as u64);
+    let expected = commitment.commitments.iter().rev()
+        .fold( EdwardsPoint::identity(), |acc, point| acc * x + point );
+    expected == &ED25519_BASEPOINT_TABLE * &share.value
+}
+
+/// Verifies every received share against its sender's published commitments, then sums the
+/// verified shares into this participant's final secret share and the commitments into the
+/// group public key. Fails closed: a single participant sending a share that doesn't match its
+/// own commitment aborts the whole key generation, since there is no safe way to exclude a
+/// cheating participant without re-running DKG.
+pub fn dkg_finalize(own_index: ParticipantIndex, threshold: u32,
+    commitments: &[DkgCommitment], shares_to_me: &[DkgShare])
+    -> Result<FrostKeyShare, ErrorToBeSpecified>
+{
+    // Fails closed also on the *absence* of a share: accepting whatever subset of shares happened
+    // to arrive would let a participant who contributed a commitment, then withheld its share,
+    // silently leave `secret_share` shy of the amount every other participant assumes - this
+    // must be caught here rather than surfacing later as an unexplained bad signature.
+    for commitment in commitments
+    {
+        if !shares_to_me.iter().any( |s| s.from == commitment.participant )
+        {
+            return Err( ErrorToBeSpecified::TODO(
+                format!("No DKG share received from participant {} who broadcast a commitment",
+                    commitment.participant) ) );
+        }
+    }
+
+    let mut secret_share = Scalar::zero();
+    for share in shares_to_me
+    {
+        let commitment = commitments.iter().find( |c| c.participant == share.from )
+            .ok_or_else( || ErrorToBeSpecified::TODO(
+                format!("No DKG commitment broadcast by participant {}", share.from) ) )?;
+        if !verify_share(commitment, share)
+        {
+            return Err( ErrorToBeSpecified::TODO(
+                format!("Participant {} sent a DKG share inconsistent with its own commitments", share.from) ) );
+        }
+        secret_share = secret_share + share.value;
+    }
+
+    let group_public_key = commitments.iter()
+        .fold( EdwardsPoint::identity(), |acc, c| acc + c.commitments[0] );
+    let verification_shares = commitments.iter().map( |c| c.participant ).collect::<Vec<_>>();
+
+    Ok( FrostKeyShare{
+        own_index, threshold, n: verification_shares.len() as u32,
+        secret_share, group_public_key: group_public_key.compress() } )
+}
+
+
+
+// --- Threshold signing (FROST, round 1 + round 2) -------------------------------------------
+
+/// A device's per-signature nonce commitments, published in round 1 before it knows which
+/// message is being signed or who else is participating.
+#[derive(Clone, Copy, Debug)]
+pub struct NonceCommitment
+{
+    pub participant: ParticipantIndex,
+    pub hiding: EdwardsPoint,   // D_i = g^{d_i}
+    pub binding: EdwardsPoint,  // E_i = g^{e_i}
+}
+
+struct Nonces { hiding: Scalar, binding: Scalar }
+
+fn sample_nonces() -> Nonces
+{
+    let mut rng = OsRng{};
+    let mut sample = ||
+    {
+        let mut bytes = [0u8; 64];
+        rng.fill_bytes(&mut bytes);
+        Scalar::from_bytes_mod_order_wide(&bytes)
+    };
+    Nonces{ hiding: sample(), binding: sample() }
+}
+
+/// This device's share of the final signature, computed in round 2 once the coordinator has
+/// announced the full set of `NonceCommitment`s participating in this signature.
+#[derive(Clone, Copy, Debug)]
+pub struct SignatureShare
+{
+    pub participant: ParticipantIndex,
+    pub z: Scalar,
+}
+
+fn binding_factor(index: ParticipantIndex, message: &[u8], commitments: &[NonceCommitment]) -> Scalar
+{
+    let mut commitments_bytes = Vec::new();
+    for c in commitments
+    {
+        commitments_bytes.extend_from_slice(&c.participant.to_le_bytes());
+        commitments_bytes.extend_from_slice(c.hiding.compress().as_bytes());
+        commitments_bytes.extend_from_slice(c.binding.compress().as_bytes());
+    }
+    hash_to_scalar( &[ &index.to_le_bytes(), message, &commitments_bytes ] )
+}
+
+fn group_commitment(message: &[u8], commitments: &[NonceCommitment]) -> EdwardsPoint
+{
+    commitments.iter().fold( EdwardsPoint::identity(), |acc, c|
+    {
+        let rho = binding_factor(c.participant, message, commitments);
+        acc + c.hiding + c.binding * rho
+    } )
+}
+
+fn challenge(r: &EdwardsPoint, group_public_key: &CompressedEdwardsY, message: &[u8]) -> Scalar
+    { hash_to_scalar( &[ r.compress().as_bytes(), group_public_key.as_bytes(), message ] ) }
+
+
+/// One participant's share of a jointly-controlled `ProfileId`, the output of `dkg_finalize`.
+/// `ProfileId` is still `multihash(group_public_key)`, exactly as for a single-device
+/// `Ed25519Signer` - a verifier cannot tell a profile is threshold-controlled at all.
+#[derive(Clone)]
+pub struct FrostKeyShare
+{
+    own_index: ParticipantIndex,
+    threshold: u32,
+    n: u32,
+    secret_share: Scalar,
+    group_public_key: CompressedEdwardsY,
+}
+
+impl FrostKeyShare
+{
+    pub fn own_index(&self) -> ParticipantIndex { self.own_index }
+    pub fn threshold(&self) -> u32 { self.threshold }
+}
+
+
+/// A device's participation in one FROST signing ceremony. `ThresholdEd25519Signer` itself is
+/// intentionally *not* a drop-in `Signer`: a single device can never produce a full signature
+/// alone, so `sign()` would either block forever or have to lie. Round 1 can run ahead of time
+/// (it doesn't depend on the message); round 2 needs the message and the final set of
+/// commitments, normally gathered by a coordinating device over a `HomeSession` channel - see
+/// `AsyncThresholdSigner`.
+pub struct ThresholdEd25519Signer
+{
+    key_share: FrostKeyShare,
+    profile_id: ProfileId,
+    nonces: Option<Nonces>,
+}
+
+impl ThresholdEd25519Signer
+{
+    /// Derives `profile_id` from the group public key using `id_factory`, exactly like
+    /// `Ed25519Signer::new` does for a single-device key - a verifier sees an ordinary
+    /// multihash(public_key) under whatever algorithm the node is configured to trust, and cannot
+    /// tell the group key is threshold-controlled at all.
+    pub fn new(key_share: FrostKeyShare, id_factory: &ProfileIdFactory) -> Result<Self, ErrorToBeSpecified>
+    {
+        let public_key = PublicKey( key_share.group_public_key.as_bytes().to_vec() );
+        let profile_id = id_factory.profile_id(&public_key)?;
+        Ok( Self{ key_share, profile_id, nonces: None } )
+    }
+
+    pub fn profile_id(&self) -> &ProfileId { &self.profile_id }
+
+    pub fn public_key(&self) -> PublicKey
+        { PublicKey( self.key_share.group_public_key.as_bytes().to_vec() ) }
+
+    /// Round 1: publish this device's nonce commitments for the signature about to happen.
+    /// Must be called exactly once per signature and the resulting commitment broadcast to the
+    /// coordinator before round 2 starts.
+    pub fn round1(&mut self) -> NonceCommitment
+    {
+        let nonces = sample_nonces();
+        let commitment = NonceCommitment{
+            participant: self.key_share.own_index,
+            hiding: &ED25519_BASEPOINT_TABLE * &nonces.hiding,
+            binding: &ED25519_BASEPOINT_TABLE * &nonces.binding };
+        self.nonces = Some(nonces);
+        commitment
+    }
+
+    /// Round 2: given the coordinator's chosen signing set and message, produce this device's
+    /// signature share `z_i`.
+    pub fn round2(&mut self, message: &[u8], commitments: &[NonceCommitment]) -> Result<SignatureShare, ErrorToBeSpecified>
+    {
+        let nonces = self.nonces.take()
+            .ok_or_else( || ErrorToBeSpecified::TODO( "round2 called before round1".to_owned() ) )?;
+        let own_index = self.key_share.own_index;
+        let rho = binding_factor(own_index, message, commitments);
+        let r = group_commitment(message, commitments);
+        let c = challenge(&r, &self.key_share.group_public_key, message);
+        let participating: Vec<_> = commitments.iter().map( |nc| nc.participant ).collect();
+        let lambda = lagrange_coefficient(own_index, &participating);
+        let z = nonces.hiding + nonces.binding * rho + lambda * c * self.key_share.secret_share;
+        Ok( SignatureShare{ participant: own_index, z } )
+    }
+}
+
+// Participant `index`'s public verification share `g^{s_i}`, i.e. the sum over every dealer's
+// Feldman-committed polynomial evaluated at `index` - exactly the secret-sharing math
+// `verify_share` uses to check one dealer's share, just summed across all dealers instead of
+// checked against one. This is the same quantity `dkg_finalize` implicitly trusts every
+// `secret_share` to equal; recomputing it here is what lets `aggregate_signature` catch a
+// participant that lies about its signature share instead of only noticing via a bad signature.
+fn verification_share(index: ParticipantIndex, dkg_commitments: &[DkgCommitment]) -> EdwardsPoint
+{
+    let x = Scalar::from(index as u64);
+    dkg_commitments.iter().fold( EdwardsPoint::identity(), |acc, c|
+    {
+        let dealer_contribution = c.commitments.iter().rev()
+            .fold( EdwardsPoint::identity(), |acc2, point| acc2 * x + point );
+        acc + dealer_contribution
+    } )
+}
+
+/// Run by the coordinating device: verifies each signature share against the sender's DKG
+/// verification share, then combines the shares gathered from (at least) `threshold`
+/// participants into a single standard Ed25519 `Signature` - no different from one produced by a
+/// lone `Ed25519Signer`, so `Ed25519Validator` accepts it unchanged. `dkg_commitments` must be the
+/// same broadcast commitments `dkg_finalize` was run with, so the group's verification shares can
+/// be recomputed rather than trusted from the signer.
+pub fn aggregate_signature(group_public_key: &[u8], message: &[u8], dkg_commitments: &[DkgCommitment],
+    commitments: &[NonceCommitment], shares: &[SignatureShare]) -> Result<Signature, ErrorToBeSpecified>
+{
+    let group_public_key_compressed = CompressedEdwardsY::from_slice(group_public_key);
+    let r = group_commitment(message, commitments);
+    let c = challenge(&r, &group_public_key_compressed, message);
+
+    let participating: Vec<_> = commitments.iter().map( |nc| nc.participant ).collect();
+    for share in shares
+    {
+        let commitment = commitments.iter().find( |nc| nc.participant == share.participant )
+            .ok_or_else( || ErrorToBeSpecified::TODO(
+                format!("Signature share from unknown participant {}", share.participant) ) )?;
+        let rho = binding_factor(share.participant, message, commitments);
+        let lambda = lagrange_coefficient(share.participant, &participating);
+        let expected = commitment.hiding + commitment.binding * rho
+            + verification_share(share.participant, dkg_commitments) * (lambda * c);
+        let actual = &ED25519_BASEPOINT_TABLE * &share.z;
+        if actual != expected
+        {
+            return Err( ErrorToBeSpecified::TODO(
+                format!("Signature share from participant {} failed verification", share.participant) ) );
+        }
+    }
+
+    let z: Scalar = shares.iter().map( |s| s.z ).sum();
+    let mut signature_bytes = Vec::with_capacity(64);
+    signature_bytes.extend_from_slice( r.compress().as_bytes() );
+    signature_bytes.extend_from_slice( z.as_bytes() );
+    Ok( Signature(signature_bytes) )
+}
+
+
+
+// --- Async façade over a HomeSession channel ------------------------------------------------
+
+/// What signing participants exchange with the coordinator over the `Call` channel opened for
+/// the threshold-signing `ApplicationId`.
+#[derive(Clone, Debug)]
+pub enum FrostMessage
+{
+    NonceCommitment(NonceCommitment),
+    SigningRequest{ message: Vec<u8>, commitments: Vec<NonceCommitment> },
+    SignatureShare(SignatureShare),
+}
+
+/// Drives a `ThresholdEd25519Signer` through both rounds over a generic duplex channel, so
+/// callers don't have to hand-roll the round1/round2 bookkeeping every time they need a
+/// signature from a multi-device profile. In production the `channel` is the `Stream`/`Sink`
+/// pair backing one side of a `Call` opened via `Home::call` to the profile's own other devices.
+pub struct AsyncThresholdSigner<Ch>
+{
+    signer: Rc<std::cell::RefCell<ThresholdEd25519Signer>>,
+    channel: Ch,
+}
+
+impl<Ch> AsyncThresholdSigner<Ch>
+where Ch: Stream<Item=FrostMessage, Error=ErrorToBeSpecified>
+        + Sink<SinkItem=FrostMessage, SinkError=ErrorToBeSpecified> + 'static
+{
+    pub fn new(signer: ThresholdEd25519Signer, channel: Ch) -> Self
+        { Self{ signer: Rc::new(std::cell::RefCell::new(signer)), channel } }
+
+    /// Participant-side: publish our round-1 commitment, wait for the coordinator's signing
+    /// request, compute and send our round-2 share.
+    pub fn participate(self) -> Box< Future<Item=(), Error=ErrorToBeSpecified> >
+    {
+        let signer = self.signer;
+        let commitment = signer.borrow_mut().round1();
+        let fut = self.channel.send( FrostMessage::NonceCommitment(commitment) )
+            .and_then( move |channel| channel.into_future()
+                .map_err( |(e, _channel)| e ) )
+            .and_then( move |(request, channel)| match request
+            {
+                Some( FrostMessage::SigningRequest{ message, commitments } ) =>
+                {
+                    let share = match signer.borrow_mut().round2(&message, &commitments)
+                    {
+                        Ok(share) => share,
+                        Err(e) => return Box::new( future::err(e) )
+                            as Box<Future<Item=(), Error=ErrorToBeSpecified>>,
+                    };
+                    Box::new( channel.send( FrostMessage::SignatureShare(share) ).map( |_| () ) )
+                },
+                _ => Box::new( future::err( ErrorToBeSpecified::TODO(
+                    "Expected a FROST signing request, got something else or nothing".to_owned() ) ) ),
+            } );
+        Box::new(fut)
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crypto::{Ed25519Validator, SignatureValidator};
+
+    #[test]
+    fn test_dkg_and_threshold_sign_roundtrip()
+    {
+        let participants: Vec<ParticipantIndex> = vec![1, 2, 3];
+        let threshold = 2;
+
+        let round1: Vec<_> = participants.iter()
+            .map( |&i| dkg_round1(i, &participants, threshold) )
+            .collect();
+        let commitments: Vec<DkgCommitment> = round1.iter().map( |(c, _)| c.clone() ).collect();
+
+        let key_shares: Vec<FrostKeyShare> = participants.iter().map( |&j|
+        {
+            let shares_to_j: Vec<DkgShare> = round1.iter()
+                .flat_map( |(_, shares)| shares.iter().cloned() )
+                .filter( |s| s.to == j )
+                .collect();
+            dkg_finalize(j, threshold, &commitments, &shares_to_j).unwrap()
+        } ).collect();
+
+        // Any `threshold`-sized subset must be able to sign; exercise that with participants 1 and 2.
+        let id_factory = ProfileIdFactory::default();
+        let mut signer1 = ThresholdEd25519Signer::new(key_shares[0].clone(), &id_factory).unwrap();
+        let mut signer2 = ThresholdEd25519Signer::new(key_shares[1].clone(), &id_factory).unwrap();
+        let public_key = signer1.public_key();
+
+        let nc1 = signer1.round1();
+        let nc2 = signer2.round1();
+        let signing_commitments = vec![nc1, nc2];
+
+        let message = b"frost threshold signature roundtrip";
+        let share1 = signer1.round2(message, &signing_commitments).unwrap();
+        let share2 = signer2.round2(message, &signing_commitments).unwrap();
+
+        let signature = aggregate_signature( public_key.0.as_slice(), message, &commitments,
+            &signing_commitments, &[share1, share2] ).unwrap();
+
+        let validator = Ed25519Validator::default();
+        assert!( validator.validate_signature(&public_key, message, &signature).unwrap() );
+    }
+}
@@ -2,13 +2,24 @@ use bytes::{Buf, BufMut, BytesMut, IntoBuf};
 use std::error::Error;
 use std::mem;
 use std::rc::Rc;
+use std::sync::Arc;
 
 use bincode::{deserialize, serialize};
 use futures::{future, Future};
 use tokio_core::net::TcpStream;
 use tokio_io::io;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use rustls::{
+    self, Certificate, ClientConfig, ServerConfig, ClientCertVerified, ClientCertVerifier,
+    ClientHello, ResolvesClientCert, ResolvesServerCert, RootCertStore, ServerCertVerified,
+    ServerCertVerifier, SignatureScheme, TLSError,
+};
+use rustls::sign::{CertifiedKey, Signer as RustlsSigner, SigningKey};
+use webpki::DNSNameRef;
 
 use ::*;
+use crypto::CompositeValidator;
 
 
 #[derive(Deserialize, Clone, Debug, Eq, Hash, PartialEq, PartialOrd, Serialize)]
@@ -84,4 +95,293 @@ pub fn temp_tcp_handshake_until_tls_is_implemented(socket: TcpStream, signer: Rc
 
     let (reader, writer) = socket.split();
     temp_handshake_until_tls_is_implemented(reader, writer, signer)
+}
+
+
+
+// The self-signed "certificate" we present is not a CA-issued X.509 cert in the usual sense:
+// it just carries the profile's claimed identity (profile_id, public_key) so our own verifier
+// can check it, while still being a real enough X.509 structure (valid SPKI + a one-time
+// self-signing signature produced via `SignerRemoteKeyPair`) for rustls/webpki to parse.
+//
+// `SignerRemoteKeyPair` only covers that one-time cert-generation signature. It is NOT reused
+// for the *live* TLS handshake signature (the CertificateVerify message rustls computes on every
+// connection) - rustls only knows how to produce that from a `rustls::PrivateKey`'s raw DER
+// bytes, which `Signer` deliberately never exposes (the whole point of the abstraction is to
+// allow key material that never lives in one place, e.g. the threshold signer). Instead we hand
+// rustls a `ProfileSigningKey`/`ProfileTlsSigner` pair that calls back into `Signer::sign` for
+// every live signature, via a `ResolvesClientCert`/`ResolvesServerCert` cert resolver rather than
+// `set_single_client_cert`/`set_single_cert` (which require real private key bytes).
+struct SignerRemoteKeyPair
+{
+    signer: Rc<Signer>,
+}
+
+impl rcgen::RemoteKeyPair for SignerRemoteKeyPair
+{
+    fn public_key(&self) -> &[u8] { self.signer.public_key().0.as_slice() }
+
+    fn sign(&self, msg: &[u8]) -> Result<Vec<u8>, rcgen::Error>
+        { Ok( self.signer.sign(msg).0.into() ) }
+
+    fn algorithm(&self) -> &'static rcgen::SignatureAlgorithm { &rcgen::PKCS_ED25519 }
+}
+
+
+/// Produces the live TLS handshake signature for rustls, by forwarding to `Signer::sign` - the
+/// same key the self-signed cert's SPKI advertises, but never materialized as raw key bytes here.
+struct ProfileTlsSigner
+{
+    signer: Rc<Signer>,
+}
+
+impl RustlsSigner for ProfileTlsSigner
+{
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, TLSError>
+        { Ok( self.signer.sign(message).0.into() ) }
+
+    fn get_scheme(&self) -> SignatureScheme { SignatureScheme::ED25519 }
+}
+
+struct ProfileSigningKey
+{
+    signer: Rc<Signer>,
+}
+
+impl SigningKey for ProfileSigningKey
+{
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<Box<RustlsSigner>>
+    {
+        if offered.contains(&SignatureScheme::ED25519)
+            { Some( Box::new( ProfileTlsSigner{ signer: self.signer.clone() } ) ) }
+        else
+            { None }
+    }
+
+    fn algorithm(&self) -> rustls::internal::pemfile::SignatureAlgorithm
+        { rustls::internal::pemfile::SignatureAlgorithm::ED25519 }
+}
+
+
+/// Always resolves to the same single self-signed profile cert/key, on both the client-auth and
+/// server-auth sides of a handshake - there is exactly one identity a `Signer` can present.
+struct ProfileCertResolver
+{
+    cert: Certificate,
+    key: Arc<ProfileSigningKey>,
+}
+
+impl ResolvesClientCert for ProfileCertResolver
+{
+    fn resolve(&self, _acceptable_issuers: &[&[u8]], _sigschemes: &[SignatureScheme]) -> Option<CertifiedKey>
+        { Some( CertifiedKey::new( vec![ self.cert.clone() ], self.key.clone() as Arc<SigningKey> ) ) }
+
+    fn has_certs(&self) -> bool { true }
+}
+
+impl ResolvesServerCert for ProfileCertResolver
+{
+    fn resolve(&self, _client_hello: ClientHello) -> Option<CertifiedKey>
+        { Some( CertifiedKey::new( vec![ self.cert.clone() ], self.key.clone() as Arc<SigningKey> ) ) }
+}
+
+
+pub(crate) fn self_signed_profile_cert(signer: Rc<Signer>) -> Result<ProfileCertResolver, ErrorToBeSpecified>
+{
+    let key_pair = rcgen::KeyPair::from_remote( Box::new( SignerRemoteKeyPair{ signer: signer.clone() } ) )
+        .map_err( |e| ErrorToBeSpecified::TODO( format!("{}", e) ) )?;
+
+    let mut params = rcgen::CertificateParams::default();
+    params.alg = &rcgen::PKCS_ED25519;
+    params.key_pair = Some(key_pair);
+    params.distinguished_name = rcgen::DistinguishedName::new();
+    params.distinguished_name.push( rcgen::DnType::CommonName, hex_encode( signer.profile_id().0.as_slice() ) );
+
+    let cert = rcgen::Certificate::from_params(params)
+        .map_err( |e| ErrorToBeSpecified::TODO( format!("{}", e) ) )?;
+    let cert_der = cert.serialize_der()
+        .map_err( |e| ErrorToBeSpecified::TODO( format!("{}", e) ) )?;
+
+    Ok( ProfileCertResolver{ cert: rustls::Certificate(cert_der), key: Arc::new( ProfileSigningKey{ signer } ) } )
+}
+
+
+// Extracts (profile_id, public_key) as claimed by a presented certificate, without trusting
+// either value yet - the caller still has to run them through MultiHashProfileValidator.
+pub(crate) fn claimed_identity_from_cert(cert: &Certificate) -> Result<(ProfileId, PublicKey), ErrorToBeSpecified>
+{
+    let (_, parsed) = x509_parser::parse_x509_certificate(cert.0.as_slice())
+        .map_err( |e| ErrorToBeSpecified::TODO( format!("Malformed peer certificate: {}", e) ) )?;
+
+    let public_key = PublicKey( parsed.tbs_certificate.subject_pki.subject_public_key.data.to_vec() );
+
+    let common_name = parsed.tbs_certificate.subject.iter_common_name().next()
+        .and_then( |cn| cn.as_str().ok() )
+        .ok_or_else( || ErrorToBeSpecified::TODO( "Peer certificate carries no ProfileId".to_owned() ) )?;
+    let profile_id = ProfileId( hex_decode(common_name)
+        .map_err( |e| ErrorToBeSpecified::TODO( format!("Invalid ProfileId encoding: {}", e) ) )? );
+
+    Ok( (profile_id, public_key) )
+}
+
+
+fn hex_encode(bytes: &[u8]) -> String
+    { bytes.iter().map( |b| format!("{:02x}", b) ).collect() }
+
+fn hex_decode(hex: &str) -> Result<Vec<u8>, String>
+{
+    if hex.len() % 2 != 0 { return Err( "odd-length hex string".to_owned() ) }
+    (0..hex.len()).step_by(2)
+        .map( |i| u8::from_str_radix(&hex[i..i+2], 16).map_err( |e| e.to_string() ) )
+        .collect()
+}
+
+
+// Confirms a presented certificate is internally consistent (ProfileId == multihash(public_key));
+// used from both the client and server sides of `tls_handshake`, since in both directions we
+// verify the peer's *profile*, not a CA chain. It checks no signature itself: neither the
+// certificate's self-signature nor any other signature is run through `Ed25519Validator` here.
+pub(crate) struct ProfileCertVerifier
+{
+    validator: Arc<CompositeValidator>,
+    // Whether the server side of a handshake using this verifier must receive a client cert at
+    // all. `tls_handshake` always presents one on both ends, so it sets this `true`; QUIC's
+    // client side doesn't support client certs yet (see `quic_handshake`), so its server-side
+    // verifier sets this `false` rather than rejecting every connection before it starts.
+    mandatory: bool,
+}
+
+impl ProfileCertVerifier
+{
+    pub(crate) fn new(validator: Arc<CompositeValidator>, mandatory: bool) -> Self
+        { Self{ validator, mandatory } }
+
+    fn check(&self, certs: &[Certificate]) -> Result<(ProfileId, PublicKey), TLSError>
+    {
+        let leaf = certs.first()
+            .ok_or_else( || TLSError::General( "Peer presented no certificate".to_owned() ) )?;
+        let (profile_id, public_key) = claimed_identity_from_cert(leaf)
+            .map_err( |e| TLSError::General( format!("{:?}", e) ) )?;
+        let valid = self.validator.validate_profile(&public_key, &profile_id)
+            .map_err( |e| TLSError::General( format!("{:?}", e) ) )?;
+        if !valid
+        {
+            return Err( TLSError::General(
+                format!("ProfileId {:?} does not match public key in certificate", profile_id) ) );
+        }
+        Ok( (profile_id, public_key) )
+    }
+}
+
+impl ServerCertVerifier for ProfileCertVerifier
+{
+    fn verify_server_cert(&self, _roots: &RootCertStore, presented_certs: &[Certificate],
+        _dns_name: DNSNameRef, _ocsp_response: &[u8]) -> Result<ServerCertVerified, TLSError>
+    {
+        self.check(presented_certs).map( |_| ServerCertVerified::assertion() )
+    }
+}
+
+impl ClientCertVerifier for ProfileCertVerifier
+{
+    fn client_auth_root_subjects(&self, _dns_name: Option<&DNSNameRef>) -> Option<rustls::DistinguishedNames>
+        { Some(rustls::DistinguishedNames::new()) }
+
+    fn client_auth_mandatory(&self, _dns_name: Option<&DNSNameRef>) -> Option<bool>
+        { Some(self.mandatory) }
+
+    fn verify_client_cert(&self, presented_certs: &[Certificate], _dns_name: Option<&DNSNameRef>)
+        -> Result<ClientCertVerified, TLSError>
+    {
+        self.check(presented_certs).map( |_| ClientCertVerified::assertion() )
+    }
+}
+
+
+// Real, mutually-authenticated replacement for `temp_handshake_until_tls_is_implemented`: runs a
+// TLS session over any duplex stream, not just a raw TcpStream, where both sides present a
+// self-signed cert binding their ProfileId to their Ed25519 public key, and both sides verify the
+// peer's cert the same way MultiHashProfileValidator would - no CA chain, just "does this
+// ProfileId really hash this key". Generic over the socket so transports that wrap a TcpStream in
+// something else first (e.g. `Obfuscated`'s encrypted duplex) can still run this same handshake
+// on top, instead of only ever terminating on a bare socket.
+pub fn tls_handshake<S>(socket: S, signer: Rc<Signer>, validator: Arc<CompositeValidator>, is_dialer: bool)
+    -> Box< Future<Item=(impl std::io::Read, impl std::io::Write, PeerContext), Error=ErrorToBeSpecified> >
+where S: std::io::Read + std::io::Write + AsyncRead + AsyncWrite + 'static
+{
+    let resolver = match self_signed_profile_cert(signer.clone()) {
+        Ok(resolver) => Arc::new(resolver),
+        Err(e) => return Box::new( future::err(e) ),
+    };
+    // Both sides of `tls_handshake` always present a self-signed profile cert, so a missing
+    // client cert is as much a failure here as a missing server cert.
+    let verifier = Arc::new( ProfileCertVerifier::new(validator, true) );
+
+    if is_dialer
+    {
+        let mut config = ClientConfig::new();
+        config.dangerous().set_certificate_verifier(verifier);
+        config.client_auth_cert_resolver = resolver;
+        let connector: TlsConnector = Arc::new(config).into();
+
+        // Peers are addressed by ProfileId, not DNS name; our verifier ignores this entirely,
+        // so any syntactically valid placeholder works here.
+        let dns_name = DNSNameRef::try_from_ascii_str("mercury.home")
+            .expect("constant string, always a valid DNS name");
+
+        let handshake_fut = connector.connect(dns_name, socket)
+            .map_err( |e| ErrorToBeSpecified::TODO( format!("TLS handshake failed: {}", e) ) )
+            .and_then( move |tls_stream|
+            {
+                let peer_certs = tls_stream.get_ref().1.get_peer_certificates()
+                    .unwrap_or_else(Vec::new);
+                let (peer_profile_id, peer_public_key) = match claimed_identity_from_cert(
+                    peer_certs.first()
+                        .ok_or_else( || ErrorToBeSpecified::TODO( "Peer presented no certificate".to_owned() ) )? )
+                {
+                    Ok(identity) => identity,
+                    Err(e) => return Err(e),
+                };
+                let peer_ctx = PeerContext::new(signer, peer_public_key, peer_profile_id);
+                let (reader, writer) = tls_stream.split();
+                Ok( (reader, writer, peer_ctx) )
+            } );
+        Box::new(handshake_fut)
+    }
+    else
+    {
+        let mut config = ServerConfig::new(verifier);
+        config.cert_resolver = resolver;
+        let acceptor: TlsAcceptor = Arc::new(config).into();
+
+        let handshake_fut = acceptor.accept(socket)
+            .map_err( |e| ErrorToBeSpecified::TODO( format!("TLS handshake failed: {}", e) ) )
+            .and_then( move |tls_stream|
+            {
+                let peer_certs = tls_stream.get_ref().1.get_peer_certificates()
+                    .unwrap_or_else(Vec::new);
+                let (peer_profile_id, peer_public_key) = match claimed_identity_from_cert(
+                    peer_certs.first()
+                        .ok_or_else( || ErrorToBeSpecified::TODO( "Peer presented no certificate".to_owned() ) )? )
+                {
+                    Ok(identity) => identity,
+                    Err(e) => return Err(e),
+                };
+                let peer_ctx = PeerContext::new(signer, peer_public_key, peer_profile_id);
+                let (reader, writer) = tls_stream.split();
+                Ok( (reader, writer, peer_ctx) )
+            } );
+        Box::new(handshake_fut)
+    }
+}
+
+
+pub fn tls_tcp_handshake(socket: TcpStream, signer: Rc<Signer>, validator: Arc<CompositeValidator>)
+    -> Box< Future<Item=(impl std::io::Read, impl std::io::Write, PeerContext), Error=ErrorToBeSpecified> >
+{
+    match socket.set_nodelay(true) {
+        Ok(_) => {},
+        Err(e) => return Box::new( future::err( ErrorToBeSpecified::TODO( e.description().to_owned() ) ) ),
+    };
+    tls_handshake(socket, signer, validator, true)
 }
\ No newline at end of file
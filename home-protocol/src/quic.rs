@@ -0,0 +1,181 @@
+use std::error::Error;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use futures::sync::mpsc;
+use futures::{future, Future, Sink, Stream};
+use quinn::{ClientConfigBuilder, Endpoint, RecvStream, SendStream, ServerConfigBuilder};
+use rustls;
+use tokio_core::reactor::Handle;
+use tokio_io::io as async_io;
+
+use ::*;
+use crypto::CompositeValidator;
+use handshake::{claimed_identity_from_cert, ProfileCertVerifier};
+
+
+
+/// QUIC mirrors `temp_tcp_handshake_until_tls_is_implemented` / `tls_handshake`: dial a connection
+/// (the accepting side only has `quic_server_config`, no handshake function) and hand back a `PeerContext` built from the peer's self-signed profile cert.
+/// Unlike the TCP paths this does not hand back a `(Read, Write)` pair - QUIC connections stay
+/// open and `quic_handshake` is just the entry point; callers open additional bidirectional
+/// streams on the same `quinn::Connection` afterwards (see `QuicHomeConnection` below), instead
+/// of dialing a fresh TCP socket per `Home::call`.
+pub fn quic_handshake(endpoint: &Endpoint, addr: &SocketAddr, server_name: &str,
+    signer: Rc<Signer>, validator: Arc<CompositeValidator>)
+    -> Box< Future<Item=(QuicHomeConnection, PeerContext), Error=ErrorToBeSpecified> >
+{
+    let mut client_config = ClientConfigBuilder::default();
+    client_config.enable_keylog();
+    // `mandatory` only governs *server*-side enforcement of a *client* cert, so it's irrelevant
+    // on this side; pass `true` since a client always expects to be able to verify a server cert.
+    let verifier = Arc::new( ProfileCertVerifier::new(validator, true) );
+    client_config.set_certificate_verifier(verifier);
+    // TODO present our own self-signed profile cert for client auth once quinn exposes a hook
+    // for a caller-supplied rustls ClientConfig (it currently builds one internally);
+    // until then the home side can only authenticate us via the profile-layer `register`/`login`
+    // handshake carried over the first stream, not via QUIC client certs.
+
+    let connecting = match endpoint.connect_with(client_config.build(), addr, server_name) {
+        Ok(connecting) => connecting,
+        Err(e) => return Box::new( future::err( ErrorToBeSpecified::TODO( format!("{}", e) ) ) ),
+    };
+
+    let fut = connecting
+        .map_err( |e| ErrorToBeSpecified::TODO( format!("QUIC handshake failed: {}", e) ) )
+        .and_then( move |new_conn|
+        {
+            let peer_certs = new_conn.connection.authentication_data().peer_certificates
+                .unwrap_or_else(Vec::new);
+            let (peer_profile_id, peer_public_key) = match claimed_identity_from_cert(
+                peer_certs.first()
+                    .ok_or_else( || ErrorToBeSpecified::TODO( "Peer presented no certificate".to_owned() ) )? )
+            {
+                Ok(identity) => identity,
+                Err(e) => return Err(e),
+            };
+            let peer_ctx = PeerContext::new(signer, peer_public_key, peer_profile_id);
+            let home_conn = QuicHomeConnection{ connection: new_conn.connection };
+            Ok( (home_conn, peer_ctx) )
+        } );
+    Box::new(fut)
+}
+
+
+/// One QUIC connection to a Home, able to open a fresh bidirectional stream per `ApplicationId`
+/// instead of the one-TCP-connection-per-call model `temp_tcp_handshake_until_tls_is_implemented`
+/// forced on `Home::call`. Because QUIC streams are independent, a slow or stalled stream does not
+/// head-of-line-block any other stream's traffic. `HomeSession::checkin_app` and `Home::call` do
+/// not use this type: nothing outside this module opens streams through it.
+#[derive(Clone)]
+pub struct QuicHomeConnection
+{
+    connection: quinn::Connection,
+}
+
+impl QuicHomeConnection
+{
+    /// Opens a new bidirectional stream dedicated to `app`, analogous to dialing a fresh
+    /// `TcpStream` in the old model but multiplexed over the already-authenticated connection.
+    pub fn open_app_stream(&self, _app: &ApplicationId)
+        -> Box< Future<Item=(SendStream, RecvStream), Error=ErrorToBeSpecified> >
+    {
+        let fut = self.connection.open_bi()
+            .map_err( |e| ErrorToBeSpecified::TODO( format!("Failed to open QUIC stream: {}", e) ) );
+        Box::new(fut)
+    }
+
+    /// Opens a fresh QUIC stream for `app`, writes `init_payload` as its first frame, and hands
+    /// back the rest of the stream as a `Sink`/`Stream` pair of `AppMessageFrame` - the same shape
+    /// `CallMessages` exposes. Each call gets its own QUIC stream instead of funnelling all call
+    /// traffic through one shared channel. Neither `Home::call` nor `HomeSession::checkin_app`
+    /// calls this.
+    pub fn call(&self, app: &ApplicationId, init_payload: AppMessageFrame, handle: &Handle)
+        -> Box< Future<Item=(mpsc::Sender<AppMessageFrame>,
+            Box< Stream<Item=AppMessageFrame, Error=ErrorToBeSpecified> >), Error=ErrorToBeSpecified> >
+    {
+        let handle = handle.clone();
+        let fut = self.open_app_stream(app)
+            .and_then( move |(send_stream, recv_stream)|
+                write_app_frame(send_stream, init_payload)
+                    .map( move |send_stream| pump_app_stream(send_stream, recv_stream, &handle) ) );
+        Box::new(fut)
+    }
+}
+
+
+const APP_FRAME_LEN_PREFIX_SIZE: usize = 4;
+
+/// Writes one `AppMessageFrame` to a QUIC stream as a little-endian length prefix followed by the
+/// payload bytes, the same framing discipline `temp_handshake_until_tls_is_implemented` uses for
+/// `AuthenticationInfo`, just applied to app call traffic instead.
+fn write_app_frame(writer: SendStream, frame: AppMessageFrame)
+    -> Box< Future<Item=SendStream, Error=ErrorToBeSpecified> >
+{
+    let mut buf = Vec::with_capacity(APP_FRAME_LEN_PREFIX_SIZE + frame.0.len());
+    buf.extend_from_slice( &(frame.0.len() as u32).to_le_bytes() );
+    buf.extend_from_slice( &frame.0 );
+    let fut = async_io::write_all(writer, buf)
+        .map( |(writer, _buf)| writer )
+        .map_err( |e| ErrorToBeSpecified::TODO( format!("QUIC app frame write failed: {}", e) ) );
+    Box::new(fut)
+}
+
+/// Reads one length-prefixed `AppMessageFrame` back off a QUIC stream; the inverse of `write_app_frame`.
+fn read_app_frame(reader: RecvStream)
+    -> Box< Future<Item=(RecvStream, AppMessageFrame), Error=ErrorToBeSpecified> >
+{
+    let fut = async_io::read_exact(reader, [0u8; APP_FRAME_LEN_PREFIX_SIZE])
+        .map_err( |e| ErrorToBeSpecified::TODO( format!("QUIC app frame length read failed: {}", e) ) )
+        .and_then( |(reader, len_buf)|
+        {
+            let len = u32::from_le_bytes(len_buf) as usize;
+            async_io::read_exact(reader, vec![0u8; len])
+                .map( |(reader, bytes)| (reader, AppMessageFrame(bytes)) )
+                .map_err( |e| ErrorToBeSpecified::TODO( format!("QUIC app frame body read failed: {}", e) ) )
+        } );
+    Box::new(fut)
+}
+
+/// Relays `AppMessageFrame`s between an mpsc channel pair and one QUIC stream's reader/writer
+/// halves, so the caller only has to speak `Sink`/`Stream` of `AppMessageFrame` without knowing
+/// it's backed by a QUIC stream underneath - mirroring how `tunnel::serve_forward` hides a raw
+/// socket behind a `Call`'s message channel.
+fn pump_app_stream(send_stream: SendStream, recv_stream: RecvStream, handle: &Handle)
+    -> (mpsc::Sender<AppMessageFrame>, Box< Stream<Item=AppMessageFrame, Error=ErrorToBeSpecified> >)
+{
+    let (outbound_sender, outbound_receiver) = mpsc::channel(1);
+    let write_pump = outbound_receiver
+        .map_err( |_| ErrorToBeSpecified::TODO( "App call channel closed".to_owned() ) )
+        .fold( send_stream, |writer, frame| write_app_frame(writer, frame) )
+        .map( |_writer| () )
+        .map_err( |e| debug!("QUIC app stream write pump ended with error: {:?}", e) );
+    handle.spawn(write_pump);
+
+    let inbound = futures::stream::unfold( recv_stream, |reader|
+        Some( read_app_frame(reader).map( |(reader, frame)| (frame, reader) ) ) );
+    (outbound_sender, Box::new(inbound))
+}
+
+
+pub fn quic_server_config(signer: Rc<Signer>, validator: Arc<CompositeValidator>)
+    -> Result<quinn::ServerConfig, ErrorToBeSpecified>
+{
+    let resolver = Arc::new( handshake::self_signed_profile_cert(signer)? );
+    // quic_handshake's client side has no client-cert support yet (see its TODO) - making this
+    // `mandatory` would fail every QUIC connection during the handshake itself, before
+    // quic_handshake ever gets a chance to hand back a PeerContext. Verify a client cert if one
+    // is presented, but don't require it the way the mutually-authenticated TCP tls_handshake does.
+    let verifier = Arc::new( ProfileCertVerifier::new(validator, false) );
+
+    // `resolver` also implements `ResolvesClientCert`/`ResolvesServerCert` the same way
+    // `tls_handshake` uses it, so plug it into quinn's underlying rustls::ServerConfig directly
+    // rather than through `ServerConfigBuilder::certificate`, which only accepts a raw private key
+    // (something a `Signer` deliberately never exposes - see `self_signed_profile_cert`).
+    let mut rustls_config = rustls::ServerConfig::new(verifier);
+    rustls_config.cert_resolver = resolver;
+    let quinn_config = quinn::ServerConfig{ crypto: Arc::new(rustls_config), ..Default::default() };
+
+    Ok( ServerConfigBuilder::new(quinn_config).build() )
+}
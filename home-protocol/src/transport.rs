@@ -0,0 +1,429 @@
+use std::cmp::min;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use bytes::{BufMut, BytesMut};
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use elligator2::{MontgomeryPoint, Representative};
+use futures::{future, Future};
+use generic_array::GenericArray;
+use rand::{rngs::OsRng, RngCore};
+use tokio_core::net::TcpStream;
+use tokio_io::io as async_io;
+use tokio_io::{AsyncRead, AsyncWrite};
+use x25519_dalek::{EphemeralSecret, PublicKey as X25519PublicKey};
+
+use ::*;
+use crypto::CompositeValidator;
+use handshake;
+use handshake::tls_handshake;
+
+
+
+/// Picks how a `Home` connection's bytes look on the wire. All three produce the same
+/// `(Reader, Writer, PeerContext)` shape so `HomeClientCapnProto` never has to know which one
+/// was used - only which `Transport` to dial with has to be decided up front, typically per
+/// network the persona is currently reachable from.
+pub trait Transport
+{
+    type Reader: std::io::Read + 'static;
+    type Writer: std::io::Write + 'static;
+
+    fn dial(&self, socket: TcpStream, signer: Rc<Signer>)
+        -> Box< Future<Item=(Self::Reader, Self::Writer, PeerContext), Error=ErrorToBeSpecified> >;
+}
+
+
+/// The original unauthenticated, unencrypted handshake, kept around as the simplest option.
+pub struct Plain;
+
+impl Transport for Plain
+{
+    type Reader = async_io::ReadHalf<TcpStream>;
+    type Writer = async_io::WriteHalf<TcpStream>;
+
+    fn dial(&self, socket: TcpStream, signer: Rc<Signer>)
+        -> Box< Future<Item=(Self::Reader, Self::Writer, PeerContext), Error=ErrorToBeSpecified> >
+    {
+        handshake::temp_tcp_handshake_until_tls_is_implemented(socket, signer)
+    }
+}
+
+
+/// Mutually-authenticated TLS, see `handshake::tls_handshake`.
+pub struct Tls
+{
+    pub validator: Arc<CompositeValidator>,
+}
+
+impl Transport for Tls
+{
+    type Reader = Box<std::io::Read>;
+    type Writer = Box<std::io::Write>;
+
+    fn dial(&self, socket: TcpStream, signer: Rc<Signer>)
+        -> Box< Future<Item=(Self::Reader, Self::Writer, PeerContext), Error=ErrorToBeSpecified> >
+    {
+        let validator = self.validator.clone();
+        let fut = tls_handshake(socket, signer, validator, true)
+            .map( |(reader, writer, ctx)|
+                ( Box::new(reader) as Box<std::io::Read>, Box::new(writer) as Box<std::io::Write>, ctx ) );
+        Box::new(fut)
+    }
+}
+
+
+/// An obfs4/o5-style pluggable transport: an Elligator2-encoded X25519 handshake makes the
+/// first bytes on the wire indistinguishable from uniform random (no recognizable TLS
+/// ClientHello, no Mercury-specific magic), then every following record is length-padded and
+/// encrypted with a key derived from the resulting shared secret. The identity handshake that
+/// runs on top is the same mutually-authenticated `tls_handshake` the plain `Tls` transport uses -
+/// the obfuscation layer only hides that a TLS-like handshake is happening at all, it must not
+/// also drop back to an unauthenticated identity exchange.
+pub struct Obfuscated
+{
+    pub validator: Arc<CompositeValidator>,
+}
+
+impl Transport for Obfuscated
+{
+    type Reader = Box<std::io::Read>;
+    type Writer = Box<std::io::Write>;
+
+    fn dial(&self, socket: TcpStream, signer: Rc<Signer>)
+        -> Box< Future<Item=(Self::Reader, Self::Writer, PeerContext), Error=ErrorToBeSpecified> >
+    {
+        let (socket_reader, socket_writer) = socket.split();
+        let validator = self.validator.clone();
+        let fut = obfuscated_handshake(socket_reader, socket_writer, true)
+            .and_then( move |duplex| tls_handshake(duplex, signer, validator, true) )
+            .map( |(reader, writer, ctx)|
+                ( Box::new(reader) as Box<std::io::Read>, Box::new(writer) as Box<std::io::Write>, ctx ) );
+        Box::new(fut)
+    }
+}
+
+
+/// Glues an `ObfuscatedReader`/`ObfuscatedWriter` pair back into a single duplex stream, so the
+/// encrypted tunnel can be handed to `tls_handshake` the same way a bare socket would be.
+pub struct ObfuscatedDuplex<R, W>
+{
+    reader: ObfuscatedReader<R>,
+    writer: ObfuscatedWriter<W>,
+}
+
+impl<R: std::io::Read, W> Read for ObfuscatedDuplex<R, W>
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.reader.read(buf) }
+}
+
+impl<R, W: std::io::Write> Write for ObfuscatedDuplex<R, W>
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.writer.write(buf) }
+    fn flush(&mut self) -> io::Result<()> { self.writer.flush() }
+}
+
+impl<R: std::io::Read + AsyncRead, W> AsyncRead for ObfuscatedDuplex<R, W> {}
+
+impl<R, W: std::io::Write + AsyncWrite> AsyncWrite for ObfuscatedDuplex<R, W>
+{
+    fn shutdown(&mut self) -> futures::Poll<(), io::Error> { self.writer.shutdown() }
+}
+
+
+fn obfuscated_handshake<R, W>(reader: R, writer: W, is_dialer: bool)
+    -> Box< Future<Item=ObfuscatedDuplex<R, W>, Error=ErrorToBeSpecified> >
+where R: std::io::Read + AsyncRead + 'static,
+      W: std::io::Write + AsyncWrite + 'static
+{
+    let mut rng = OsRng{};
+
+    // Only a fraction of Curve25519 points have an Elligator2 representative; keep sampling
+    // fresh ephemeral keys (cheap) until one maps, rather than leaking the all-important "this
+    // point isn't representable" bit to an observer by falling back to any other encoding.
+    let (own_secret, own_representative) = loop
+    {
+        let own_secret = EphemeralSecret::new(&mut rng);
+        let own_public = X25519PublicKey::from(&own_secret);
+        if let Some(representative) = Representative::from_montgomery_point(
+            &MontgomeryPoint(own_public.to_bytes()), &mut rng)
+        {
+            break (own_secret, representative);
+        }
+    };
+
+    // A per-connection random seed folded into key derivation, so two connections between the
+    // same pair of peers never reuse keystream or produce distinguishable record sizes/timing
+    // even if the DH output alone were ever to repeat. Both sides contribute one, appended right
+    // after the Elligator2 representative, and combine them (order-independent) below - a seed
+    // only either side picked unilaterally would differ between dialer and acceptor, since they
+    // each sample their own.
+    let mut own_seed = [0u8; 32];
+    OsRng{}.fill_bytes(&mut own_seed);
+    let mut own_handshake_msg = Vec::with_capacity(64);
+    own_handshake_msg.extend_from_slice(&own_representative.to_bytes());
+    own_handshake_msg.extend_from_slice(&own_seed);
+
+    let send_fut = async_io::write_all(writer, own_handshake_msg)
+        .map_err( |e| ErrorToBeSpecified::TODO( format!("obfuscated handshake write: {}", e) ) );
+    let recv_fut = async_io::read_exact(reader, vec![0u8; 64])
+        .map_err( |e| ErrorToBeSpecified::TODO( format!("obfuscated handshake read: {}", e) ) );
+
+    let fut = send_fut.join(recv_fut)
+        .and_then( move |((writer, _), (reader, peer_handshake_msg))|
+        {
+            let mut peer_representative_bytes = [0u8; 32];
+            peer_representative_bytes.copy_from_slice(&peer_handshake_msg[..32]);
+            let peer_representative = Representative::from_bytes(&peer_representative_bytes);
+            let peer_public_point = peer_representative.to_montgomery_point();
+            let peer_public = X25519PublicKey::from(peer_public_point.0);
+            let shared_secret = own_secret.diffie_hellman(&peer_public);
+
+            let mut seed = [0u8; 32];
+            for i in 0..32 { seed[i] = own_seed[i] ^ peer_handshake_msg[32 + i]; }
+
+            // Separate keys per direction, the same way TLS derives distinct client/server write
+            // keys: reusing one (key, nonce-counter-from-zero) pair for both directions of a
+            // connection would let an observer XOR same-nonce ciphertexts from either side and
+            // recover the XOR of their plaintexts.
+            let (initiator_key, responder_key) = derive_directional_keys(shared_secret.as_bytes(), &seed);
+            let (write_key, read_key) = if is_dialer { (initiator_key, responder_key) } else { (responder_key, initiator_key) };
+
+            let reader = ObfuscatedReader::new(reader, read_key);
+            let writer = ObfuscatedWriter::new(writer, write_key);
+            Ok( ObfuscatedDuplex{ reader, writer } )
+        } );
+    Box::new(fut)
+}
+
+fn derive_directional_keys(shared_secret: &[u8; 32], seed: &[u8; 32]) -> ([u8; 32], [u8; 32])
+{
+    ( derive_record_key(shared_secret, seed, b"initiator"), derive_record_key(shared_secret, seed, b"responder") )
+}
+
+fn derive_record_key(shared_secret: &[u8; 32], seed: &[u8; 32], direction_label: &[u8]) -> [u8; 32]
+{
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    hasher.input(b"mercury-obfuscated-transport-v1");
+    hasher.input(shared_secret);
+    hasher.input(seed);
+    hasher.input(direction_label);
+    let mut key = [0u8; 32];
+    key.copy_from_slice( hasher.result().as_slice() );
+    key
+}
+
+
+const MAX_RECORD_PLAINTEXT: usize = 16 * 1024;
+// Padding is bucketed rather than exact-fit so the wire size of a record leaks only which
+// bucket a payload falls into, not its precise length.
+const PADDING_BUCKET: usize = 256;
+
+fn nonce_for(counter: u64) -> Nonce
+{
+    let mut bytes = [0u8; 12];
+    bytes[4..].copy_from_slice(&counter.to_le_bytes());
+    *Nonce::from_slice(&bytes)
+}
+
+
+// `ObfuscatedReader`/`ObfuscatedWriter` sit directly on a non-blocking socket (or whatever the
+// caller hands in, which may itself only deliver a few bytes per call) - a `read_exact`/
+// `write_all` against that would either block the reactor thread or, on a `WouldBlock`, abandon
+// a partially-read length/body or partially-written record forever, desyncing the frame boundary
+// for the rest of the connection. The state below resumes exactly where a partial read left off
+// on the next `read()` call instead.
+enum ReadState
+{
+    Length{ buf: [u8; 2], have: usize },
+    Body{ len: usize, buf: Vec<u8>, have: usize },
+}
+
+/// Decrypts and unpads inbound obfuscated records into a flat plaintext byte stream.
+pub struct ObfuscatedReader<R>
+{
+    inner: R,
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+    plaintext: BytesMut,
+    state: ReadState,
+}
+
+impl<R: std::io::Read> ObfuscatedReader<R>
+{
+    fn new(inner: R, key: [u8; 32]) -> Self
+    {
+        Self{ inner, cipher: ChaCha20Poly1305::new( GenericArray::from_slice(&key) ), counter: 0,
+              plaintext: BytesMut::new(), state: ReadState::Length{ buf: [0u8; 2], have: 0 } }
+    }
+
+    // Drives the current record to completion, issuing as many non-blocking `inner.read()` calls
+    // as are immediately satisfiable. Returns `Ok(true)` once a full record has been decrypted
+    // into `self.plaintext`. Returns `Ok(false)` only on a clean EOF between records (nothing
+    // read yet for the next length prefix). A `WouldBlock` (or any other I/O error) from `inner`
+    // propagates immediately via `?`, leaving `self.state` exactly where it was so the next call
+    // resumes mid-length or mid-body instead of losing its place in the framing.
+    fn advance(&mut self) -> io::Result<bool>
+    {
+        loop
+        {
+            match &mut self.state
+            {
+                ReadState::Length{ buf, have } if *have == 2 =>
+                {
+                    let record_len = u16::from_be_bytes(*buf) as usize;
+                    self.state = ReadState::Body{ len: record_len, buf: vec![0u8; record_len], have: 0 };
+                },
+                ReadState::Length{ buf, have } =>
+                {
+                    let was_empty = *have == 0;
+                    let n = self.inner.read(&mut buf[*have..])?;
+                    if n == 0
+                    {
+                        if was_empty { return Ok(false); } // clean EOF between records
+                        return Err( io::Error::new( io::ErrorKind::UnexpectedEof,
+                            "obfuscated transport closed mid-record" ) );
+                    }
+                    *have += n;
+                },
+                ReadState::Body{ len, buf, have } if *have == *len =>
+                {
+                    let nonce = nonce_for(self.counter);
+                    self.counter += 1;
+                    let padded = self.cipher.decrypt(&nonce, buf.as_slice()).map_err( |_|
+                        io::Error::new( io::ErrorKind::InvalidData, "obfuscated record failed to authenticate" ) )?;
+                    // Padded plaintext is [u16 real length][real bytes][random padding].
+                    let real_len = u16::from_be_bytes([padded[0], padded[1]]) as usize;
+                    self.plaintext.extend_from_slice(&padded[2 .. 2 + real_len]);
+                    self.state = ReadState::Length{ buf: [0u8; 2], have: 0 };
+                    return Ok(true);
+                },
+                ReadState::Body{ buf, have, .. } =>
+                {
+                    let n = self.inner.read(&mut buf[*have..])?;
+                    if n == 0
+                    {
+                        return Err( io::Error::new( io::ErrorKind::UnexpectedEof,
+                            "obfuscated transport closed mid-record" ) );
+                    }
+                    *have += n;
+                },
+            }
+        }
+    }
+}
+
+impl<R: std::io::Read> Read for ObfuscatedReader<R>
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        if self.plaintext.is_empty() && !self.advance()? { return Ok(0); } // clean EOF
+        let n = min(buf.len(), self.plaintext.len());
+        buf[..n].copy_from_slice(&self.plaintext[..n]);
+        let _ = self.plaintext.split_to(n);
+        Ok(n)
+    }
+}
+
+impl<R: std::io::Read + AsyncRead> AsyncRead for ObfuscatedReader<R> {}
+
+
+/// Pads and encrypts outbound bytes into length-obfuscated records. `pending` holds the
+/// already-encrypted wire bytes of a record (length prefix + ciphertext) that haven't made it to
+/// `inner` yet - a short/`WouldBlock` write from `inner` only ever loses its place within
+/// `pending`, never re-derives or re-sends a record, so the peer's decrypt-side record boundary
+/// can never drift from ours.
+pub struct ObfuscatedWriter<W>
+{
+    inner: W,
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+    pending: Vec<u8>,
+    pending_pos: usize,
+}
+
+impl<W: std::io::Write> ObfuscatedWriter<W>
+{
+    fn new(inner: W, key: [u8; 32]) -> Self
+    {
+        Self{ inner, cipher: ChaCha20Poly1305::new( GenericArray::from_slice(&key) ), counter: 0,
+              pending: Vec::new(), pending_pos: 0 }
+    }
+
+    // Pushes as much of `self.pending[self.pending_pos..]` to `inner` as is immediately
+    // accepted. A `WouldBlock` from `inner` is swallowed here (not propagated) so the caller can
+    // tell "still buffered" apart from a real failure; any other I/O error propagates.
+    fn flush_pending(&mut self) -> io::Result<()>
+    {
+        while self.pending_pos < self.pending.len()
+        {
+            match self.inner.write(&self.pending[self.pending_pos..])
+            {
+                Ok(0) => return Err( io::Error::new( io::ErrorKind::WriteZero,
+                    "obfuscated transport accepted zero bytes" ) ),
+                Ok(n) => self.pending_pos += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        self.pending.clear();
+        self.pending_pos = 0;
+        Ok(())
+    }
+}
+
+impl<W: std::io::Write> Write for ObfuscatedWriter<W>
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>
+    {
+        self.flush_pending()?;
+        if !self.pending.is_empty()
+        {
+            // Previous record still draining; refuse new plaintext rather than interleaving
+            // another record's bytes into an in-flight one.
+            return Err( io::Error::new( io::ErrorKind::WouldBlock, "obfuscated record still flushing" ) );
+        }
+
+        let chunk_len = min(buf.len(), MAX_RECORD_PLAINTEXT);
+        let chunk = &buf[..chunk_len];
+
+        let bucketed_len = ( (2 + chunk_len + PADDING_BUCKET - 1) / PADDING_BUCKET ) * PADDING_BUCKET;
+        let mut padded = BytesMut::with_capacity(bucketed_len);
+        padded.put_u16_be(chunk_len as u16);
+        padded.put_slice(chunk);
+        let mut padding = vec![0u8; bucketed_len - padded.len()];
+        OsRng{}.fill_bytes(&mut padding);
+        padded.put_slice(&padding);
+
+        let nonce = nonce_for(self.counter);
+        self.counter += 1;
+        let ciphertext = self.cipher.encrypt(&nonce, padded.as_ref())
+            .map_err( |_| io::Error::new(io::ErrorKind::Other, "failed to encrypt obfuscated record") )?;
+
+        self.pending = Vec::with_capacity(2 + ciphertext.len());
+        self.pending.extend_from_slice( &(ciphertext.len() as u16).to_be_bytes() );
+        self.pending.extend_from_slice(&ciphertext);
+        self.pending_pos = 0;
+
+        self.flush_pending()?; // best-effort; any remainder stays buffered for the next call
+        Ok(chunk_len)
+    }
+
+    fn flush(&mut self) -> io::Result<()>
+    {
+        self.flush_pending()?;
+        if !self.pending.is_empty()
+        {
+            return Err( io::Error::new( io::ErrorKind::WouldBlock, "obfuscated record still flushing" ) );
+        }
+        self.inner.flush()
+    }
+}
+
+impl<W: std::io::Write + AsyncWrite> AsyncWrite for ObfuscatedWriter<W>
+{
+    fn shutdown(&mut self) -> futures::Poll<(), io::Error> { self.inner.shutdown() }
+}
@@ -0,0 +1,256 @@
+use std::cell::Cell;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+use bincode::{deserialize, serialize};
+use futures::{future, Future, Sink, Stream};
+use tokio_core::net::{TcpStream, UdpSocket};
+use tokio_core::reactor::Handle;
+use tokio_io::io as async_io;
+use tokio_io::AsyncRead;
+
+use ::*;
+
+
+
+/// Which side of the pair dials the target. `LocalToRemote` means the *initiator* wants to reach
+/// a service near the *acceptor* (the common "expose a local port to my other persona" case is
+/// actually the mirror image of this - see `ForwardRequest` doc).
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum ForwardDirection { LocalToRemote, RemoteToLocal }
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum ForwardProtocol { Tcp, Udp }
+
+
+/// Sent as the `init_payload` of a `Home::call` to ask the accepting persona's device to open a
+/// forward. `direction` is from the initiator's point of view: `LocalToRemote` dials
+/// `target_host:target_port` *from the acceptor* and streams bytes back to the initiator (the
+/// initiator is exposing its own caller to a service that's only reachable from the acceptor's
+/// network); `RemoteToLocal` is the mirror, the acceptor's bytes get relayed to a service the
+/// initiator dials locally.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ForwardRequest
+{
+    pub direction: ForwardDirection,
+    pub protocol: ForwardProtocol,
+    pub target_host: String,
+    pub target_port: u16,
+}
+
+impl ForwardRequest
+{
+    pub fn to_payload(&self) -> Result<AppMessageFrame, ErrorToBeSpecified>
+    {
+        serialize(self)
+            .map( AppMessageFrame )
+            .map_err( |e| ErrorToBeSpecified::TODO( e.description().to_owned() ) )
+    }
+
+    pub fn from_payload(payload: &AppMessageFrame) -> Result<Self, ErrorToBeSpecified>
+    {
+        deserialize(&payload.0)
+            .map_err( |e| ErrorToBeSpecified::TODO( e.description().to_owned() ) )
+    }
+}
+
+
+/// One chunk of a forwarded connection, framed into an `AppMessageFrame`. `Shutdown` propagates
+/// a half-close of the forwarded socket (e.g. the local TCP side did `shutdown(Write)`) so the
+/// other end can do the same to its half, rather than the whole `Call` dying as if it were an
+/// error.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+enum ForwardFrame
+{
+    Data(Vec<u8>),
+    Shutdown,
+}
+
+impl ForwardFrame
+{
+    fn to_payload(&self) -> Result<AppMessageFrame, ErrorToBeSpecified>
+    {
+        serialize(self)
+            .map( AppMessageFrame )
+            .map_err( |e| ErrorToBeSpecified::TODO( e.description().to_owned() ) )
+    }
+
+    fn from_payload(payload: &AppMessageFrame) -> Result<Self, ErrorToBeSpecified>
+    {
+        deserialize(&payload.0)
+            .map_err( |e| ErrorToBeSpecified::TODO( e.description().to_owned() ) )
+    }
+}
+
+
+const FORWARD_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Running byte counters for one forward, so a home can report/limit how much traffic a tunneled
+/// call is pushing without having to inspect `AppMessageFrame`s anywhere else.
+#[derive(Clone, Default)]
+pub struct ForwardFlowStats
+{
+    sent: Rc<Cell<u64>>,
+    received: Rc<Cell<u64>>,
+}
+
+impl ForwardFlowStats
+{
+    pub fn bytes_sent(&self) -> u64 { self.sent.get() }
+    pub fn bytes_received(&self) -> u64 { self.received.get() }
+
+    fn add_sent(&self, n: usize) { self.sent.set( self.sent.get() + n as u64 ) }
+    fn add_received(&self, n: usize) { self.received.set( self.received.get() + n as u64 ) }
+}
+
+
+/// Accepts a `ForwardRequest` carried as a `Call`'s `init_payload`: dials (TCP) or binds (UDP)
+/// the requested target and relays bytes between it and `messages` until either side closes.
+/// This is what `HomeSessionServer::checkin_app` drives for every incoming `Call` whose payload
+/// deserializes as a `ForwardRequest`. `request.direction` is not read: the target is always
+/// dialed or bound from this (accepting) side, so a `RemoteToLocal` request is served exactly like
+/// a `LocalToRemote` one, and nothing here has the initiator dial a service locally.
+pub fn serve_forward(request: ForwardRequest, messages: CallMessages, handle: Handle)
+    -> Box< Future<Item=ForwardFlowStats, Error=ErrorToBeSpecified> >
+{
+    let target: SocketAddr = match format!("{}:{}", request.target_host, request.target_port).parse()
+    {
+        Ok(addr) => addr,
+        Err(e) => return Box::new( future::err( ErrorToBeSpecified::TODO(
+            format!("Invalid forward target: {}", e) ) ) ),
+    };
+
+    match request.protocol
+    {
+        ForwardProtocol::Tcp => serve_tcp_forward(target, messages, handle),
+        ForwardProtocol::Udp => serve_udp_forward(target, messages, handle),
+    }
+}
+
+
+fn serve_tcp_forward(target: SocketAddr, messages: CallMessages, handle: Handle)
+    -> Box< Future<Item=ForwardFlowStats, Error=ErrorToBeSpecified> >
+{
+    let stats = ForwardFlowStats::default();
+    let fut = TcpStream::connect(&target, &handle)
+        .map_err( |e| ErrorToBeSpecified::TODO( format!("Forward target connect failed: {}", e) ) )
+        .and_then( move |socket|
+        {
+            let (socket_reader, socket_writer) = socket.split();
+            let (call_sink, call_stream) = messages.split();
+
+            let socket_to_call = relay_socket_to_call(socket_reader, call_sink, stats.clone());
+            let call_to_socket = relay_call_to_socket(call_stream, socket_writer, stats.clone());
+
+            socket_to_call.join(call_to_socket).map( move |_| stats )
+        } );
+    Box::new(fut)
+}
+
+fn relay_socket_to_call<R>(reader: R, call_sink: futures::stream::SplitSink<CallMessages>, stats: ForwardFlowStats)
+    -> Box< Future<Item=(), Error=ErrorToBeSpecified> >
+where R: std::io::Read + AsyncRead + 'static
+{
+    // `done` tracks whether we've already emitted the one `Shutdown` frame for this forward; once
+    // set, `unfold` returns `None` and the stream ends instead of re-reading an already-EOF socket
+    // and sending `Shutdown` forever.
+    let fut = futures::stream::unfold( (reader, vec![0u8; FORWARD_CHUNK_SIZE], false), |(reader, mut buf, done)|
+    {
+        if done { return None; }
+        Some( async_io::read(reader, buf.split_off(0))
+            .map_err( |e| ErrorToBeSpecified::TODO( format!("Forward target read failed: {}", e) ) )
+            .map( move |(reader, buf, n)| ( (buf.clone(), n), (reader, buf, n == 0) ) ) )
+    } )
+    .map_err( |e: ErrorToBeSpecified| e )
+    .and_then( move |(buf, n)|
+    {
+        let frame = if n == 0 { ForwardFrame::Shutdown } else { ForwardFrame::Data(buf[..n].to_vec()) };
+        stats.add_received(n);
+        frame.to_payload()
+    } )
+    .forward( call_sink.sink_map_err( |_| ErrorToBeSpecified::TODO( "Call channel closed".to_owned() ) ) )
+    .map( |_| () );
+    Box::new(fut)
+}
+
+fn relay_call_to_socket<W>(call_stream: futures::stream::SplitStream<CallMessages>, mut writer: W, stats: ForwardFlowStats)
+    -> Box< Future<Item=(), Error=ErrorToBeSpecified> >
+where W: std::io::Write + AsyncWrite + 'static
+{
+    let fut = call_stream
+        .map_err( |e| e )
+        .for_each( move |payload|
+        {
+            match ForwardFrame::from_payload(&payload)?
+            {
+                ForwardFrame::Data(bytes) =>
+                {
+                    stats.add_sent( bytes.len() );
+                    // NOTE this is a plain, non-waiting `std::io::Write::write_all`: when the
+                    // target socket cannot take all of `bytes` at once, its `WouldBlock` error
+                    // ends the relay instead of waiting for the socket to drain.
+                    writer.write_all(&bytes)
+                        .map_err( |e| ErrorToBeSpecified::TODO( format!("Forward target write failed: {}", e) ) )?;
+                },
+                ForwardFrame::Shutdown =>
+                {
+                    // Best-effort half-close: propagate the peer's shutdown onto our half of the
+                    // forwarded socket so a TCP FIN on one side of the tunnel produces one on the
+                    // other, instead of silently dropping the signal.
+                    let _ = writer.shutdown();
+                },
+            }
+            Ok(())
+        } );
+    Box::new(fut)
+}
+
+
+fn serve_udp_forward(target: SocketAddr, messages: CallMessages, handle: Handle)
+    -> Box< Future<Item=ForwardFlowStats, Error=ErrorToBeSpecified> >
+{
+    let stats = ForwardFlowStats::default();
+    let bind_addr: SocketAddr = if target.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" }.parse().unwrap();
+    let socket = match UdpSocket::bind(&bind_addr, &handle)
+    {
+        Ok(socket) => socket,
+        Err(e) => return Box::new( future::err( ErrorToBeSpecified::TODO(
+            format!("Failed to bind UDP forward socket: {}", e) ) ) ),
+    };
+
+    // UDP has no connection or half-close to propagate - each ForwardFrame::Data frame is one
+    // datagram sent to `target`, and each datagram read back from `target` becomes one
+    // ForwardFrame::Data frame.
+    let (call_sink, call_stream) = messages.split();
+    let socket = Rc::new(socket);
+    let socket_for_send = socket.clone();
+
+    let stats_for_uplink = stats.clone();
+    let uplink = call_stream
+        .map_err( |e| e )
+        .for_each( move |payload| match ForwardFrame::from_payload(&payload)?
+        {
+            ForwardFrame::Data(bytes) =>
+            {
+                stats_for_uplink.add_sent( bytes.len() );
+                socket_for_send.send_to(&bytes, &target)
+                    .map( |_| () )
+                    .map_err( |e| ErrorToBeSpecified::TODO( format!("UDP forward send failed: {}", e) ) )
+            },
+            ForwardFrame::Shutdown => Ok(()),
+        } );
+
+    let stats_for_downlink = stats.clone();
+    let downlink = futures::stream::unfold( (socket, vec![0u8; FORWARD_CHUNK_SIZE]), |(socket, mut buf)|
+    {
+        Some( future::result( socket.recv_from(&mut buf) )
+            .map_err( |e| ErrorToBeSpecified::TODO( format!("UDP forward recv failed: {}", e) ) )
+            .map( move |(n, _from)| ( (buf[..n].to_vec(), n), (socket, buf) ) ) )
+    } )
+    .and_then( move |(bytes, n)| { stats_for_downlink.add_received(n); ForwardFrame::Data(bytes).to_payload() } )
+    .forward( call_sink.sink_map_err( |_| ErrorToBeSpecified::TODO( "Call channel closed".to_owned() ) ) )
+    .map( |_| () );
+
+    Box::new( uplink.join(downlink).map( move |_| stats ) )
+}
@@ -1,7 +1,9 @@
 use futures::future;
 use futures::{Future, Stream};
+use tokio_core::reactor;
 
 use mercury_common::*;
+use mercury_home_protocol::tunnel::{self, ForwardRequest};
 use super::*;
 
 
@@ -98,6 +100,7 @@ impl Home for HomeServer
 
 pub struct HomeSessionServer
 {
+    handle: reactor::Handle,
     // TODO
     // how to access context to get client profileId?
 }
@@ -105,8 +108,8 @@ pub struct HomeSessionServer
 
 impl HomeSessionServer
 {
-    pub fn new() -> Self
-        { Self{} }
+    pub fn new(handle: reactor::Handle) -> Self
+        { Self{ handle } }
 }
 
 
@@ -138,7 +141,30 @@ impl HomeSession for HomeSessionServer
         Box< Stream<Item=Call, Error=ErrorToBeSpecified> >
     {
         let (sender, receiver) = futures::sync::mpsc::channel(0);
-        Box::new( receiver.map_err( |_| ErrorToBeSpecified::TODO ) )
+        let handle = self.handle.clone();
+
+        // Any incoming Call whose init_payload is a ForwardRequest is a tunnel, not a regular
+        // app session: open the requested target and relay it over the Call's message channel,
+        // instead of handing the Call on to whatever else is listening on this checkin_app
+        // stream.
+        let tunneled = receiver
+            .map_err( |_| ErrorToBeSpecified::TODO )
+            .filter_map( move |call: Call|
+            {
+                match ForwardRequest::from_payload(&call.init_payload)
+                {
+                    Ok(request) =>
+                    {
+                        let relay = tunnel::serve_forward(request, call.messages, handle.clone())
+                            .map( |_stats| () )
+                            .map_err( |e| debug!("Forward relay ended with error: {:?}", e) );
+                        handle.spawn(relay);
+                        None
+                    },
+                    Err(_) => Some(call),
+                }
+            } );
+        Box::new(tunneled)
     }
 
     // TODO remove this after testing